@@ -62,6 +62,19 @@ pub trait Operations: Sized {
         is_last: bool,
     ) -> Result;
 
+    /// Called by the kernel to queue a batch of requests with the driver in
+    /// one go. Drivers that can submit several requests to hardware with a
+    /// single doorbell write should implement this to amortize per-request
+    /// submission overhead; the default falls back to one `queue_rq` call
+    /// per request.
+    fn queue_rqs(
+        _hw_data: ForeignBorrowed<'_, Self::HwData>,
+        _queue_data: ForeignBorrowed<'_, Self::QueueData>,
+        _rqs: &mut RequestList<Self>,
+    ) {
+        crate::build_error(crate::error::VTABLE_DEFAULT_ERROR)
+    }
+
     /// Called by the kernel to indicate that queued requests should be submitted
     fn commit_rqs(
         hw_data: ForeignBorrowed<'_, Self::HwData>,
@@ -78,8 +91,14 @@ pub trait Operations: Sized {
     ) -> Result<Self::HwData>;
 
     /// Called by the kernel to poll the device for completed requests. Only
-    /// used for poll queues.
-    fn poll(_hw_data: ForeignBorrowed<'_, Self::HwData>) -> bool {
+    /// used for poll queues. Requests the driver finds complete should be
+    /// added to `iob`; the return value is the number of requests completed
+    /// during this call, which blk-mq retires in one
+    /// `blk_mq_end_request_batch` call instead of ending each individually.
+    fn poll(
+        _hw_data: ForeignBorrowed<'_, Self::HwData>,
+        _iob: &mut IoCompletionBatch<'_, Self>,
+    ) -> usize {
         crate::build_error(crate::error::VTABLE_DEFAULT_ERROR)
     }
 
@@ -88,6 +107,201 @@ pub trait Operations: Sized {
         crate::build_error(crate::error::VTABLE_DEFAULT_ERROR)
     }
 
+    /// Called by the kernel when a dispatched request has not completed
+    /// within its deadline. The default implementation resets the timer,
+    /// which is the safe choice for drivers that have no recovery action of
+    /// their own.
+    fn timeout(_rq: &Request<Self>) -> TimeoutAction {
+        TimeoutAction::ResetTimer
+    }
+
+    /// Called by the kernel before dispatching a request to reserve a slot
+    /// in the driver's submission window. Returning `None` tells blk-mq
+    /// there is no budget available right now, so the request should be
+    /// requeued instead of handed to `queue_rq`.
+    fn get_budget(_queue_data: ForeignBorrowed<'_, Self::QueueData>) -> Option<BudgetToken> {
+        crate::build_error(crate::error::VTABLE_DEFAULT_ERROR)
+    }
+
+    /// Called by the kernel to release a budget token previously returned by
+    /// `get_budget`, once the request it was reserved for has completed or
+    /// is being requeued.
+    fn put_budget(_queue_data: ForeignBorrowed<'_, Self::QueueData>, _token: BudgetToken) {
+        crate::build_error(crate::error::VTABLE_DEFAULT_ERROR)
+    }
+}
+
+/// A budget token returned by [`Operations::get_budget`].
+///
+/// blk-mq stores this on the request (via `set_rq_budget_token`) for the
+/// duration of the request's time in flight and hands it back to
+/// [`Operations::put_budget`] once the driver is done with the request, so a
+/// driver using budgets can bound the number of in-flight requests a
+/// hardware queue will accept.
+#[derive(Copy, Clone)]
+pub struct BudgetToken(core::ffi::c_int);
+
+impl BudgetToken {
+    /// Creates a new `BudgetToken` wrapping the driver-chosen value `token`,
+    /// to be returned from [`Operations::get_budget`].
+    pub fn new(token: core::ffi::c_int) -> Self {
+        Self(token)
+    }
+}
+
+/// The action to take in response to a request timing out, returned from
+/// [`Operations::timeout`].
+pub enum TimeoutAction {
+    /// The driver could not tell whether the request has completed; reset
+    /// the request's timer and keep waiting.
+    ResetTimer,
+    /// The driver has dealt with the timed-out request (for example by
+    /// aborting it or completing it directly) and blk-mq can tear it down.
+    Done,
+}
+
+/// A safe wrapper around the C `struct io_comp_batch` passed to
+/// [`Operations::poll`].
+///
+/// A busy poll queue can retire many completed requests through a single
+/// `blk_mq_end_request_batch` call instead of ending each one individually;
+/// [`IoCompletionBatch::push`] adds one request to that batch.
+pub struct IoCompletionBatch<'a, T: Operations> {
+    iob: &'a mut bindings::io_comp_batch,
+    _p: PhantomData<T>,
+}
+
+impl<'a, T: Operations> IoCompletionBatch<'a, T> {
+    /// Constructs an `IoCompletionBatch` from the raw pointer handed to
+    /// [`OperationsVTable::poll_callback`] by blk-mq.
+    ///
+    /// # Safety
+    ///
+    /// `iob` must be a valid, exclusively-owned `io_comp_batch` for the
+    /// duration of `'a`.
+    unsafe fn from_raw(iob: *mut bindings::io_comp_batch) -> Self {
+        Self {
+            // SAFETY: `iob` is valid and exclusively owned for `'a` per this
+            // function's safety requirements.
+            iob: unsafe { &mut *iob },
+            _p: PhantomData,
+        }
+    }
+
+    /// Adds `rq` to the batch so blk-mq can end it together with the rest of
+    /// the batch.
+    ///
+    /// If the request cannot be folded into this batch, ownership of `rq` is
+    /// handed back through `Err` so the caller can complete it some other
+    /// way instead of leaking the reference.
+    pub fn push(&mut self, rq: ARef<Request<T>>) -> Result<(), ARef<Request<T>>> {
+        let ptr = ARef::into_raw(rq);
+
+        // SAFETY: `ptr` is a valid, owned request pointer obtained from
+        // `ARef::into_raw` above. `self.iob` is valid per the type
+        // invariant. `Self::complete_batch` is installed as the batch's
+        // completion hook, so blk-mq ends the whole batch with one
+        // `blk_mq_end_request_batch` call.
+        let added = unsafe {
+            bindings::blk_mq_add_to_batch(
+                ptr.as_ptr().cast(),
+                self.iob,
+                0,
+                Some(Self::complete_batch),
+            )
+        };
+
+        if added {
+            Ok(())
+        } else {
+            // SAFETY: `blk_mq_add_to_batch` returned `false`, so it did not
+            // take ownership of `ptr`; the refcount taken when it was
+            // produced is still ours to give back.
+            Err(unsafe { ARef::from_raw(ptr) })
+        }
+    }
+
+    /// Completion hook for batches built by [`IoCompletionBatch::push`]:
+    /// ends every request in `iob` with one `blk_mq_end_request_batch` call.
+    ///
+    /// # Safety
+    ///
+    /// `iob` must point to a valid `io_comp_batch`.
+    unsafe extern "C" fn complete_batch(iob: *mut bindings::io_comp_batch) {
+        // SAFETY: `iob` is valid as required by this function.
+        unsafe { bindings::blk_mq_end_request_batch(iob) };
+    }
+}
+
+impl TimeoutAction {
+    fn to_c(self) -> bindings::blk_eh_timer_return {
+        match self {
+            TimeoutAction::ResetTimer => bindings::BLK_EH_RESET_TIMER as _,
+            TimeoutAction::Done => bindings::BLK_EH_DONE as _,
+        }
+    }
+}
+
+/// A list of requests handed to [`Operations::queue_rqs`] in one batch.
+///
+/// This wraps the C `struct rq_list` that blk-mq builds by walking its
+/// plugged request list and grouping consecutive requests bound for the same
+/// hardware queue. A driver pulls requests off the front one at a time with
+/// [`RequestList::pop_front`] (or by iterating). `pop_front` only removes
+/// from the front and there is no way to push a request back, so a driver
+/// can only stop partway through and leave the untouched trailing suffix in
+/// the list; blk-mq then submits whatever is left through the ordinary
+/// [`Operations::queue_rq`] path. A driver that needs to pop the whole list
+/// and requeue some of what it popped (as `nvme_queue_rqs`/
+/// `virtio_queue_rqs` do in C) cannot do so through this type yet.
+pub struct RequestList<T: Operations> {
+    rqlist: *mut bindings::rq_list,
+    _p: PhantomData<T>,
+}
+
+impl<T: Operations> RequestList<T> {
+    /// Constructs a `RequestList` from a raw `rq_list` pointer handed to
+    /// [`OperationsVTable::queue_rqs_callback`] by blk-mq.
+    ///
+    /// # Safety
+    ///
+    /// `rqlist` must point to a valid `struct rq_list` for the duration of
+    /// the returned value's lifetime, and the caller must not alias it.
+    unsafe fn from_raw(rqlist: *mut bindings::rq_list) -> Self {
+        Self {
+            rqlist,
+            _p: PhantomData,
+        }
+    }
+
+    /// Removes and returns the request at the front of the list, taking a
+    /// refcount on it exactly as `queue_rq_callback` does for a singly
+    /// queued request.
+    pub fn pop_front(&mut self) -> Option<ARef<Request<T>>> {
+        // SAFETY: `self.rqlist` is valid by the type invariant.
+        let request_ptr = unsafe { bindings::rq_list_pop(self.rqlist) };
+        let request_ptr = NonNull::new(request_ptr)?;
+
+        // SAFETY: By C API contract, the pointee of `request_ptr` is valid and has a refcount of 1
+        #[cfg_attr(not(CONFIG_DEBUG_MISC), allow(unused_variables))]
+        let updated = unsafe { bindings::req_ref_inc_not_zero(request_ptr.as_ptr()) };
+
+        #[cfg(CONFIG_DEBUG_MISC)]
+        if !updated {
+            crate::pr_err!("Request ref was zero at queue_rqs time\n");
+        }
+
+        // SAFETY: We own a refcount that we took above. We pass that to `ARef`.
+        Some(unsafe { ARef::from_raw(request_ptr.cast::<Request<T>>()) })
+    }
+}
+
+impl<T: Operations> Iterator for RequestList<T> {
+    type Item = ARef<Request<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pop_front()
+    }
 }
 
 /// A vtable for blk-mq to interact with a block device driver.
@@ -163,6 +377,43 @@ impl<T: Operations> OperationsVTable<T> {
         }
     }
 
+    /// # Safety
+    ///
+    /// This function may only be called by blk-mq C infrastructure. The
+    /// caller must ensure that `rqlist` is valid, non-empty, and that every
+    /// request in it shares the same `mq_hctx` (blk-mq guarantees this by
+    /// grouping its plugged list before calling `queue_rqs`).
+    unsafe extern "C" fn queue_rqs_callback(rqlist: *mut bindings::rq_list) {
+        // SAFETY: The safety requirements of this function guarantee that
+        // `rqlist` is non-empty and valid.
+        let head = unsafe { (*rqlist).head };
+
+        // SAFETY: The safety requirements of this function guarantee every
+        // request in `rqlist` shares the same `mq_hctx`, so peeking at the
+        // first one is sufficient to look up the driver data for the whole
+        // batch.
+        let hctx = unsafe { (*head).mq_hctx };
+
+        // SAFETY: `driver_data` was produced by a call to `into_foreign` in
+        // `Self::init_hctx_callback`.
+        let hw_data = unsafe { T::HwData::borrow((*hctx).driver_data) };
+
+        // SAFETY: `hctx` is valid as required by this function.
+        let queue_data = unsafe { (*(*hctx).queue).queuedata };
+
+        // SAFETY: `queue.queuedata` was created by `GenDisk::try_new()` with a
+        // call to `ForeignOwnable::into_pointer()` to create `queuedata`.
+        // `ForeignOwnable::from_foreign()` is only called when the tagset is
+        // dropped, which happens after we are dropped.
+        let queue_data = unsafe { T::QueueData::borrow(queue_data) };
+
+        // SAFETY: `rqlist` is valid as required by the safety requirements of
+        // this function.
+        let mut rqs = unsafe { RequestList::from_raw(rqlist) };
+
+        T::queue_rqs(hw_data, queue_data, &mut rqs);
+    }
+
     /// # Safety
     ///
     /// This function may only be called by blk-mq C infrastructure. The caller
@@ -194,6 +445,86 @@ impl<T: Operations> OperationsVTable<T> {
         T::complete(unsafe { Request::from_ptr_mut(rq) });
     }
 
+    /// # Safety
+    ///
+    /// This function may only be called by blk-mq C infrastructure. `rq` must
+    /// point to a valid request that was dispatched to the driver and has
+    /// not yet completed. The pointee of `rq` must be valid for the duration
+    /// of this function.
+    unsafe extern "C" fn timeout_callback(
+        rq: *mut bindings::request,
+    ) -> bindings::blk_eh_timer_return {
+        // SAFETY: By the safety requirements of this function, `rq` is valid
+        // for the duration of this call.
+        let rq = unsafe { Request::from_ptr_mut(rq) };
+        T::timeout(rq).to_c()
+    }
+
+    /// # Safety
+    ///
+    /// This function may only be called by blk-mq C infrastructure. `q` must
+    /// be a valid pointer to the `struct request_queue` this driver was
+    /// registered against.
+    unsafe extern "C" fn get_budget_callback(q: *mut bindings::request_queue) -> core::ffi::c_int {
+        // SAFETY: `q` is valid as required by the safety requirements of this function.
+        let queue_data = unsafe { (*q).queuedata };
+
+        // SAFETY: `queue.queuedata` was created by `GenDisk::try_new()` with a
+        // call to `ForeignOwnable::into_pointer()` to create `queuedata`.
+        let queue_data = unsafe { T::QueueData::borrow(queue_data) };
+
+        match T::get_budget(queue_data) {
+            Some(token) => token.0,
+            // No budget available right now; blk-mq re-queues the request.
+            None => -1,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// This function may only be called by blk-mq C infrastructure. `q` must
+    /// be a valid pointer to the `struct request_queue` this driver was
+    /// registered against, and `budget_token` must have come from a previous
+    /// call to `get_budget_callback`.
+    unsafe extern "C" fn put_budget_callback(
+        q: *mut bindings::request_queue,
+        budget_token: core::ffi::c_int,
+    ) {
+        // SAFETY: `q` is valid as required by the safety requirements of this function.
+        let queue_data = unsafe { (*q).queuedata };
+
+        // SAFETY: `queue.queuedata` was created by `GenDisk::try_new()` with a
+        // call to `ForeignOwnable::into_pointer()` to create `queuedata`.
+        let queue_data = unsafe { T::QueueData::borrow(queue_data) };
+
+        T::put_budget(queue_data, BudgetToken(budget_token));
+    }
+
+    /// # Safety
+    ///
+    /// This function may only be called by blk-mq C infrastructure. `rq`
+    /// must be a valid, initialized request.
+    unsafe extern "C" fn set_rq_budget_token_callback(
+        rq: *mut bindings::request,
+        token: core::ffi::c_int,
+    ) {
+        // SAFETY: `rq` is valid for write for the duration of this call, as
+        // required by the safety requirements of this function.
+        unsafe { (*rq).budget_token = token };
+    }
+
+    /// # Safety
+    ///
+    /// This function may only be called by blk-mq C infrastructure. `rq`
+    /// must be a valid, initialized request.
+    unsafe extern "C" fn get_rq_budget_token_callback(
+        rq: *mut bindings::request,
+    ) -> core::ffi::c_int {
+        // SAFETY: `rq` is valid for read for the duration of this call, as
+        // required by the safety requirements of this function.
+        unsafe { (*rq).budget_token }
+    }
+
     /// # Safety
     ///
     /// This function may only be called by blk-mq C infrastructure. `hctx` must
@@ -201,13 +532,19 @@ impl<T: Operations> OperationsVTable<T> {
     /// previously initialized by a call to `init_hctx_callback`.
     unsafe extern "C" fn poll_callback(
         hctx: *mut bindings::blk_mq_hw_ctx,
-        _iob: *mut bindings::io_comp_batch,
+        iob: *mut bindings::io_comp_batch,
     ) -> core::ffi::c_int {
         // SAFETY: By function safety requirement, `hctx` was initialized by
         // `init_hctx_callback` and thus `driver_data` came from a call to
         // `into_foreign`.
         let hw_data = unsafe { T::HwData::borrow((*hctx).driver_data) };
-        T::poll(hw_data).into()
+
+        // SAFETY: `iob` is valid and exclusively owned for the duration of
+        // this call, as required by the safety requirements of this
+        // function.
+        let mut iob = unsafe { IoCompletionBatch::from_raw(iob) };
+
+        T::poll(hw_data, &mut iob) as _
     }
 
     /// # Safety
@@ -317,13 +654,33 @@ impl<T: Operations> OperationsVTable<T> {
 
     const VTABLE: bindings::blk_mq_ops = bindings::blk_mq_ops {
         queue_rq: Some(Self::queue_rq_callback),
-        queue_rqs: None,
+        queue_rqs: if T::HAS_QUEUE_RQS {
+            Some(Self::queue_rqs_callback)
+        } else {
+            None
+        },
         commit_rqs: Some(Self::commit_rqs_callback),
-        get_budget: None,
-        put_budget: None,
-        set_rq_budget_token: None,
-        get_rq_budget_token: None,
-        timeout: None,
+        get_budget: if T::HAS_GET_BUDGET {
+            Some(Self::get_budget_callback)
+        } else {
+            None
+        },
+        put_budget: if T::HAS_PUT_BUDGET {
+            Some(Self::put_budget_callback)
+        } else {
+            None
+        },
+        set_rq_budget_token: if T::HAS_GET_BUDGET {
+            Some(Self::set_rq_budget_token_callback)
+        } else {
+            None
+        },
+        get_rq_budget_token: if T::HAS_GET_BUDGET {
+            Some(Self::get_rq_budget_token_callback)
+        } else {
+            None
+        },
+        timeout: Some(Self::timeout_callback),
         poll: if T::HAS_POLL {
             Some(Self::poll_callback)
         } else {
@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! Streaming DMA mappings.
+//!
+//! This module provides a safe wrapper around the kernel's streaming
+//! DMA-API (`dma_map_sg`/`dma_unmap_sg`/`dma_sync_sg_*`), following the
+//! invariants documented in
+//! [`Documentation/core-api/dma-api.rst`](srctree/Documentation/core-api/dma-api.rst):
+//! a mapping must be released before the buffer it describes is handed back
+//! to the rest of the kernel, the [`DmaDirection`] passed to `map` must
+//! match how the transfer actually moves data, and CPU access to a mapped
+//! buffer on a non-coherent or bounce-buffered (swiotlb) platform must be
+//! bracketed with [`MappedSgTable::sync_for_cpu`] and
+//! [`MappedSgTable::sync_for_device`].
+//!
+//! C header: [`include/linux/dma-mapping.h`](srctree/include/linux/dma-mapping.h)
+
+use crate::{
+    bindings, block::mq::Request, device::RawDevice, error::code::*, error::Error, prelude::*,
+};
+use core::marker::PhantomData;
+
+/// The direction of a DMA transfer.
+///
+/// This corresponds to the C `enum dma_data_direction` and must match the
+/// direction of the transfer the mapping is used for: mapping a buffer
+/// `ToDevice` and then having the device write into it (or vice versa) is a
+/// coherency bug the hardware and/or IOMMU are not required to catch.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The CPU is the producer, the device is the consumer.
+    ToDevice,
+    /// The device is the producer, the CPU is the consumer.
+    FromDevice,
+    /// Both sides produce and consume; always synced, never optimized.
+    Bidirectional,
+}
+
+impl DmaDirection {
+    fn to_c(self) -> bindings::dma_data_direction {
+        match self {
+            DmaDirection::ToDevice => bindings::dma_data_direction_DMA_TO_DEVICE,
+            DmaDirection::FromDevice => bindings::dma_data_direction_DMA_FROM_DEVICE,
+            DmaDirection::Bidirectional => bindings::dma_data_direction_DMA_BIDIRECTIONAL,
+        }
+    }
+}
+
+/// One mapped DMA segment: a device-visible address and its length in
+/// bytes.
+#[derive(Copy, Clone)]
+pub struct DmaSegment {
+    /// The bus address the device should use to address this segment.
+    pub dma_addr: bindings::dma_addr_t,
+    /// The length of this segment in bytes.
+    pub len: usize,
+}
+
+/// A scatter-gather table built from a [`Request`]'s bio segments.
+///
+/// An `SgTable` starts out unmapped: it just describes the segments of the
+/// request's payload in CPU-visible form. Calling [`SgTable::map`] performs
+/// the `dma_map_sg` call and returns a [`MappedSgTable`] that hands out the
+/// translated device addresses; dropping the `MappedSgTable` calls
+/// `dma_unmap_sg` automatically. A `MappedSgTable` borrows its `SgTable`,
+/// so the mapping cannot outlive the table that describes it. Neither type
+/// borrows the [`Request`]: the borrow checker does not stop the request
+/// from being completed while a `MappedSgTable` is still alive.
+pub struct SgTable<T> {
+    sgl: bindings::sg_table,
+    _p: PhantomData<T>,
+}
+
+impl<T> SgTable<T> {
+    /// Builds an `SgTable` describing the bio segments of `rq`.
+    pub fn new(rq: &Request<T>) -> Result<Self> {
+        let mut sgl = bindings::sg_table::default();
+
+        // SAFETY: `rq` is a valid request.
+        let nr_segs = unsafe { bindings::blk_rq_nr_phys_segments(rq.as_ptr()) };
+
+        // SAFETY: `sgl` is a freshly zero-initialized `sg_table` we own;
+        // `sg_alloc_table` allocates and initializes its scatterlist with
+        // room for `nr_segs` entries.
+        let ret = unsafe { bindings::sg_alloc_table(&mut sgl, nr_segs, bindings::GFP_KERNEL) };
+        if ret != 0 {
+            return Err(Error::from_errno(ret));
+        }
+
+        // SAFETY: `rq` is a valid request, and `sgl.sgl` was allocated above
+        // with room for at least `nr_segs` entries, satisfying
+        // `blk_rq_map_sg`'s requirement on its destination array.
+        let nents = unsafe { bindings::blk_rq_map_sg(rq.queue(), rq.as_ptr(), sgl.sgl) };
+        if nents < 0 {
+            // SAFETY: `sgl` was successfully allocated by `sg_alloc_table` above.
+            unsafe { bindings::sg_free_table(&mut sgl) };
+            return Err(ENOMEM);
+        }
+        sgl.nents = nents as _;
+
+        Ok(Self {
+            sgl,
+            _p: PhantomData,
+        })
+    }
+
+    /// Maps this table against `dev` for a transfer in `direction`,
+    /// returning a [`MappedSgTable`] whose segments are valid device
+    /// addresses until it is dropped.
+    pub fn map<'a>(
+        &'a mut self,
+        dev: &'a dyn RawDevice,
+        direction: DmaDirection,
+    ) -> Result<MappedSgTable<'a, T>> {
+        // SAFETY: `dev.raw_device()` is a valid device pointer for the
+        // duration of this call, and `self.sgl` is a valid, owned
+        // `sg_table` that has not yet been mapped.
+        let mapped = unsafe {
+            bindings::dma_map_sg(
+                dev.raw_device(),
+                self.sgl.sgl,
+                self.sgl.nents,
+                direction.to_c(),
+            )
+        };
+        if mapped == 0 {
+            return Err(EIO);
+        }
+
+        Ok(MappedSgTable {
+            table: self,
+            dev,
+            direction,
+            mapped_nents: mapped as _,
+        })
+    }
+}
+
+impl<T> Drop for SgTable<T> {
+    fn drop(&mut self) {
+        // SAFETY: `self.sgl` was successfully allocated by `sg_alloc_table`
+        // in `SgTable::new` and has not been freed before, since `SgTable`
+        // does not implement `Clone` or otherwise expose `self.sgl`.
+        unsafe { bindings::sg_free_table(&mut self.sgl) };
+    }
+}
+
+/// An [`SgTable`] that has been mapped for DMA with [`SgTable::map`].
+///
+/// Dropping a `MappedSgTable` calls `dma_unmap_sg`, releasing the mapping.
+/// The borrow of the underlying [`SgTable`] ensures the mapping cannot
+/// outlive the table describing it.
+pub struct MappedSgTable<'a, T> {
+    table: &'a mut SgTable<T>,
+    dev: &'a dyn RawDevice,
+    direction: DmaDirection,
+    /// The number of entries `dma_map_sg` actually filled in, which may be
+    /// fewer than `table.sgl.nents` if adjacent or IOMMU-mapped segments
+    /// were coalesced. `dma_unmap_sg`/`dma_sync_sg_*` must still be called
+    /// with the original `table.sgl.nents`, but consumers of the mapped
+    /// `dma_address`/`dma_length` fields must not walk past this count.
+    mapped_nents: core::ffi::c_uint,
+}
+
+impl<'a, T> MappedSgTable<'a, T> {
+    /// Returns an iterator over the mapped `(dma_addr_t, len)` segments, in
+    /// the order the device should walk them.
+    pub fn segments(&self) -> impl Iterator<Item = DmaSegment> + '_ {
+        // SAFETY: `self.table.sgl` was successfully mapped by
+        // `SgTable::map` and remains valid for the lifetime of `self`.
+        // `self.mapped_nents` is the count `dma_map_sg` returned, so every
+        // index in this range was actually filled in.
+        (0..self.mapped_nents).map(move |i| {
+            let sg = unsafe { &*self.table.sgl.sgl.add(i as usize) };
+            DmaSegment {
+                dma_addr: sg.dma_address,
+                len: sg.dma_length as usize,
+            }
+        })
+    }
+
+    /// Synchronizes the mapping for CPU access, required on non-coherent or
+    /// bounce-buffered (swiotlb) platforms before the CPU reads a buffer
+    /// mapped `FromDevice` or `Bidirectional`.
+    pub fn sync_for_cpu(&self) {
+        // SAFETY: `self.dev` and `self.table.sgl` are valid for the
+        // lifetime of `self`, which outlives this call.
+        unsafe {
+            bindings::dma_sync_sg_for_cpu(
+                self.dev.raw_device(),
+                self.table.sgl.sgl,
+                self.table.sgl.nents,
+                self.direction.to_c(),
+            )
+        };
+    }
+
+    /// Synchronizes the mapping for device access, required before handing
+    /// a buffer the CPU just wrote back to the device on non-coherent or
+    /// bounce-buffered (swiotlb) platforms.
+    pub fn sync_for_device(&self) {
+        // SAFETY: `self.dev` and `self.table.sgl` are valid for the
+        // lifetime of `self`, which outlives this call.
+        unsafe {
+            bindings::dma_sync_sg_for_device(
+                self.dev.raw_device(),
+                self.table.sgl.sgl,
+                self.table.sgl.nents,
+                self.direction.to_c(),
+            )
+        };
+    }
+}
+
+impl<'a, T> Drop for MappedSgTable<'a, T> {
+    fn drop(&mut self) {
+        // SAFETY: `self.dev` and `self.table.sgl` are valid, and were
+        // mapped together by the `dma_map_sg` call in `SgTable::map` that
+        // produced this `MappedSgTable`.
+        unsafe {
+            bindings::dma_unmap_sg(
+                self.dev.raw_device(),
+                self.table.sgl.sgl,
+                self.table.sgl.nents,
+                self.direction.to_c(),
+            )
+        };
+    }
+}